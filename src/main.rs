@@ -1,134 +1,359 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ops::{Add, Mul, Sub},
+    rc::Rc,
+};
 
+pub mod sync;
 
-#[derive(Clone)]
-pub struct Graph(Rc<RefCell<Node>>);
+/// Transcendental operations and numeric identities required by the graph.
+///
+/// The built-in arithmetic nodes go through the standard [`Add`]/[`Mul`]
+/// operator traits; everything that cannot be expressed that way — the
+/// transcendental functions and the additive/multiplicative identities used to
+/// seed and accumulate adjoints in the backward pass — lives here. Callers wire
+/// the crate up for a new value type `T` by implementing this trait; an
+/// implementation is provided for `f32`.
+pub trait Transcendental: Sized {
+    /// Additive identity, used to reset adjoints before a backward pass.
+    fn zero() -> Self;
+    /// Multiplicative identity, used to seed the output adjoint.
+    fn one() -> Self;
+    fn sin(&self) -> Self;
+    fn cos(&self) -> Self;
+    fn powf(&self, exp: &Self) -> Self;
+    fn ln(&self) -> Self;
+}
+
+impl Transcendental for f32 {
+    fn zero() -> Self {
+        0f32
+    }
+
+    fn one() -> Self {
+        1f32
+    }
+
+    fn sin(&self) -> Self {
+        f32::sin(*self)
+    }
+
+    fn cos(&self) -> Self {
+        f32::cos(*self)
+    }
+
+    fn powf(&self, exp: &Self) -> Self {
+        f32::powf(*self, *exp)
+    }
+
+    fn ln(&self) -> Self {
+        f32::ln(*self)
+    }
+}
+
+/// Errors that can arise while evaluating a [`Graph`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// The nodes form a cycle, so no topological order exists and the graph
+    /// cannot be evaluated.
+    Cycle,
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Cycle => write!(f, "graph contains a cycle"),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+pub struct Graph<T>(Rc<RefCell<Node<T>>>);
+
+impl<T> Clone for Graph<T> {
+    fn clone(&self) -> Self {
+        Graph(self.0.clone())
+    }
+}
+
+/// Forward closure of an [`OperationType::Apply`] node: maps the current values
+/// of its inputs to the node's value.
+pub type ApplyFn<T> = Rc<dyn Fn(&[T]) -> T>;
+
+/// Optional derivative closure of an [`OperationType::Apply`] node: maps the
+/// current input values to the local partial derivatives of the output w.r.t.
+/// each input, aligned with the inputs, so the node can take part in the
+/// backward pass.
+pub type ApplyGradFn<T> = Rc<dyn Fn(&[T]) -> Vec<T>>;
 
-enum OperationType {
+enum OperationType<T> {
     Input(String),
-    Add(Graph, Graph),
-    Mul(Graph, Graph),
-    Sin(Graph),
-    PowF32(Graph, Graph),
+    Add(Graph<T>, Graph<T>),
+    Mul(Graph<T>, Graph<T>),
+    Sin(Graph<T>),
+    PowF32(Graph<T>, Graph<T>),
+    Apply(Vec<Graph<T>>, ApplyFn<T>, Option<ApplyGradFn<T>>),
 }
 
-pub struct Node {
+pub struct Node<T> {
     /// Operation performed by the node
-    op_type: OperationType,
-    /// Nodes which must be recalculated when the value in current node is changed
-    dependent_nodes: Vec<Graph>,
+    op_type: OperationType<T>,
     /// Cached value
-    cache: Option<f32>,
+    cache: Option<T>,
+    /// Monotonically increasing version, bumped whenever this node's value
+    /// changes (an input `set`, or an operation recompute)
+    version: u64,
+    /// Versions of the operands that produced the current `cache`, aligned with
+    /// the node's operands; a mismatch against the operands' live versions
+    /// means the cache is stale and must be recomputed
+    input_versions: Vec<u64>,
+    /// Accumulated adjoint (partial derivative of the output w.r.t. this node)
+    /// during the latest backward pass
+    adjoint: T,
 }
 
-impl Node {
-    fn new(op_type: OperationType, cache: Option<f32>) -> Node {
+impl<T: Transcendental> Node<T> {
+    fn new(op_type: OperationType<T>, cache: Option<T>) -> Node<T> {
         Node {
             op_type,
-            dependent_nodes: vec![],
             cache,
+            version: 0,
+            input_versions: vec![],
+            adjoint: T::zero(),
         }
     }
 
-    fn wrap(self) -> Graph {
+    fn wrap(self) -> Graph<T> {
         Graph(Rc::new(RefCell::new(self)))
     }
 }
 
-impl Graph {
-    pub fn create_input<I: Into<String>>(val: I) -> Graph {
+impl<T> Graph<T>
+where
+    T: Transcendental + Clone + Add<Output = T> + Mul<Output = T> + Sub<Output = T>,
+{
+    pub fn create_input<I: Into<String>>(val: I) -> Graph<T> {
         Node::new(OperationType::Input(val.into()), None).wrap()
     }
 
-    pub fn add(op1: Graph, op2: Graph) -> Graph {
-        let node = Node::new(OperationType::Add(op1.clone(), op2.clone()), None).wrap();
-        Self::add_dependent_node(&node, op1);
-        Self::add_dependent_node(&node, op2);
-        node
+    pub fn add(op1: Graph<T>, op2: Graph<T>) -> Graph<T> {
+        Node::new(OperationType::Add(op1, op2), None).wrap()
+    }
+
+    pub fn mul(op1: Graph<T>, op2: Graph<T>) -> Graph<T> {
+        Node::new(OperationType::Mul(op1, op2), None).wrap()
+    }
+
+    pub fn pow_f32(b: Graph<T>, exp: Graph<T>) -> Graph<T> {
+        Node::new(OperationType::PowF32(b, exp), None).wrap()
     }
 
-    pub fn mul(op1: Graph, op2: Graph) -> Graph {
-        let node = Node::new(OperationType::Mul(op1.clone(), op2.clone()), None).wrap();
-        Self::add_dependent_node(&node, op1);
-        Self::add_dependent_node(&node, op2);
-        node
+    pub fn sin(op: Graph<T>) -> Graph<T> {
+        Node::new(OperationType::Sin(op), None).wrap()
     }
 
-    pub fn pow_f32(b: Graph, exp: Graph) -> Graph {
-        let node = Node::new(OperationType::PowF32(b.clone(), exp.clone()), None).wrap();
-        Self::add_dependent_node(&node, b);
-        Self::add_dependent_node(&node, exp);
-        node
+    /// Build a node from an arbitrary n-ary closure over its `inputs`. The
+    /// closure receives the inputs' values in order and its result is cached
+    /// like any other operation. The node does not contribute to `backward`;
+    /// use [`Graph::apply_with_grad`] if gradients are needed.
+    pub fn apply(inputs: Vec<Graph<T>>, f: ApplyFn<T>) -> Graph<T> {
+        Node::new(OperationType::Apply(inputs, f, None), None).wrap()
     }
 
-    pub fn sin(op: Graph) -> Graph {
-        let node = Node::new(OperationType::Sin(op.clone()), None).wrap();
-        Self::add_dependent_node(&node, op);
-        node
+    /// Like [`Graph::apply`], but `df` supplies the local partial derivatives of
+    /// the output w.r.t. each input (in input order), letting the custom node
+    /// participate in reverse-mode differentiation.
+    pub fn apply_with_grad(inputs: Vec<Graph<T>>, f: ApplyFn<T>, df: ApplyGradFn<T>) -> Graph<T> {
+        Node::new(OperationType::Apply(inputs, f, Some(df)), None).wrap()
     }
 
-    fn traverse(node: &Graph) -> f32 {
-        let mut node = node.0.as_ref().borrow_mut();
-        if let &Some(cache) = &node.cache {
-            return cache;
+    /// Operands of a node, in left-to-right order.
+    fn children(node: &Graph<T>) -> Vec<Graph<T>> {
+        match &node.0.as_ref().borrow().op_type {
+            OperationType::Input(ref _s) => vec![],
+            OperationType::Add(op1, op2)
+            | OperationType::Mul(op1, op2)
+            | OperationType::PowF32(op1, op2) => vec![op1.clone(), op2.clone()],
+            OperationType::Sin(op) => vec![op.clone()],
+            OperationType::Apply(inputs, _, _) => inputs.clone(),
         }
+    }
 
-        match &node.op_type {
-            OperationType::Input(ref _s) => node.cache.unwrap(),
-            OperationType::Add(op1, op2) => {
-                let res = Self::traverse(op1) + Self::traverse(op2);
-                node.cache.replace(res);
-                res
-            }
-            OperationType::Mul(op1, op2) => {
-                let res = Self::traverse(op1) * Self::traverse(op2);
-                node.cache.replace(res);
-                res
+    /// Topologically order the nodes feeding `root` (every operand before the
+    /// node that consumes it), using an explicit work-stack rather than
+    /// recursion so arbitrarily deep graphs don't overflow the stack. A
+    /// back-edge to a node still on the active DFS path means the graph
+    /// contains a cycle, reported as [`GraphError::Cycle`].
+    fn topo_order(root: &Graph<T>) -> Result<Vec<Graph<T>>, GraphError> {
+        // State per node: `false` = on the active path, `true` = finished.
+        let mut state: HashMap<*const RefCell<Node<T>>, bool> = HashMap::new();
+        let mut order = vec![];
+        let mut stack = vec![(root.clone(), false)];
+
+        while let Some((node, processed)) = stack.pop() {
+            let ptr = Rc::as_ptr(&node.0);
+            if processed {
+                state.insert(ptr, true);
+                order.push(node);
+                continue;
             }
-            OperationType::Sin(op) => {
-                let res = Self::traverse(op).sin();
-                node.cache.replace(res);
-                res
+            if state.contains_key(&ptr) {
+                continue; // already on the path or finished
             }
-            OperationType::PowF32(b, exp) => {
-                let res = Self::traverse(b).powf(Self::traverse(exp));
-                node.cache.replace(res);
-                res
+            state.insert(ptr, false);
+            stack.push((node.clone(), true));
+            for child in Self::children(&node) {
+                match state.get(&Rc::as_ptr(&child.0)) {
+                    Some(false) => return Err(GraphError::Cycle),
+                    Some(true) => {}
+                    None => stack.push((child, false)),
+                }
             }
         }
+        Ok(order)
     }
 
-    pub fn compute(&self) -> f32 {
-        Self::traverse(self)
+    /// Live versions of a node's operands, in operand order.
+    fn child_versions(node: &Graph<T>) -> Vec<u64> {
+        Self::children(node)
+            .iter()
+            .map(|c| c.0.as_ref().borrow().version)
+            .collect()
     }
 
-    fn clear_cash(node: &Graph) {
-        let mut node = node.0.as_ref().borrow_mut();
-        let _ = node.cache.take();
-        for dep in &node.dependent_nodes {
-            Self::clear_cash(dep);
+    /// Fill a node's cache from its operands, reusing the existing cache when
+    /// every operand's version still matches the versions recorded when the
+    /// cache was last produced. On a recompute the node records the fresh
+    /// operand versions and bumps its own version so its dependents, in turn,
+    /// see the change. Inputs keep whatever value `set` stored.
+    fn eval_node(node: &Graph<T>) {
+        if let OperationType::Input(ref _s) = node.0.as_ref().borrow().op_type {
+            return;
+        }
+        let current = Self::child_versions(node);
+        {
+            let n = node.0.as_ref().borrow();
+            if n.cache.is_some() && n.input_versions == current {
+                return;
+            }
         }
+        let res = match &node.0.as_ref().borrow().op_type {
+            OperationType::Input(ref _s) => return,
+            OperationType::Add(op1, op2) => Self::value(op1) + Self::value(op2),
+            OperationType::Mul(op1, op2) => Self::value(op1) * Self::value(op2),
+            OperationType::Sin(op) => Self::value(op).sin(),
+            OperationType::PowF32(b, exp) => Self::value(b).powf(&Self::value(exp)),
+            OperationType::Apply(inputs, f, _) => {
+                let vals: Vec<T> = inputs.iter().map(Self::value).collect();
+                f(&vals)
+            }
+        };
+        let mut n = node.0.as_ref().borrow_mut();
+        n.cache.replace(res);
+        n.input_versions = current;
+        n.version += 1;
     }
 
-    pub fn set<I: Into<f32>>(&self, new_val: I) {
-        let node = self.0.as_ref().borrow_mut();
-        if let OperationType::Input(ref _s) = node.op_type {
-            drop(node);
-            Self::clear_cash(&self);
-            self.0.as_ref().borrow_mut().cache.replace(new_val.into());
+    /// Read the cached value of a node.
+    fn value(node: &Graph<T>) -> T {
+        node.0.as_ref().borrow().cache.clone().unwrap()
+    }
+
+    pub fn compute(&self) -> Result<T, GraphError> {
+        let order = Self::topo_order(self)?;
+        for node in &order {
+            Self::eval_node(node);
         }
+        Ok(Self::value(self))
+    }
+
+    /// Add `delta` into a node's accumulated adjoint.
+    fn accumulate(node: &Graph<T>, delta: T) {
+        let mut n = node.0.as_ref().borrow_mut();
+        let cur = n.adjoint.clone();
+        n.adjoint = cur + delta;
     }
 
-    fn add_dependent_node(&self, op: Graph) {
-        let mut op = op.0.as_ref().borrow_mut();
-        op.dependent_nodes.push(self.clone());
+    /// Reverse-mode automatic differentiation.
+    ///
+    /// Runs the forward pass so every node's `cache` holds its value, seeds the
+    /// output adjoint to [`Transcendental::one`], then propagates adjoints
+    /// backward through the DAG. Because nodes are shared via `Rc`,
+    /// contributions are accumulated (added) into each parent, and nodes are
+    /// visited in reverse topological order so an adjoint is fully summed
+    /// before it is pushed upstream. Returns the partial derivative of the
+    /// output w.r.t. every `Input` node, keyed by the name passed to
+    /// `create_input`. Returns [`GraphError::Cycle`] if the graph is not a DAG.
+    pub fn backward(&self) -> Result<HashMap<String, T>, GraphError> {
+        let order = Self::topo_order(self)?;
+        for node in &order {
+            Self::eval_node(node);
+        }
+
+        for n in &order {
+            n.0.as_ref().borrow_mut().adjoint = T::zero();
+        }
+        self.0.as_ref().borrow_mut().adjoint = T::one();
+
+        let mut grads = HashMap::new();
+        for node in order.iter().rev() {
+            let adjoint = node.0.as_ref().borrow().adjoint.clone();
+            match &node.0.as_ref().borrow().op_type {
+                OperationType::Input(name) => {
+                    grads.insert(name.clone(), adjoint);
+                }
+                OperationType::Add(op1, op2) => {
+                    Self::accumulate(op1, adjoint.clone());
+                    Self::accumulate(op2, adjoint);
+                }
+                OperationType::Mul(op1, op2) => {
+                    let v1 = op1.0.as_ref().borrow().cache.clone().unwrap();
+                    let v2 = op2.0.as_ref().borrow().cache.clone().unwrap();
+                    Self::accumulate(op1, adjoint.clone() * v2);
+                    Self::accumulate(op2, adjoint * v1);
+                }
+                OperationType::Sin(op) => {
+                    let v = op.0.as_ref().borrow().cache.clone().unwrap();
+                    Self::accumulate(op, adjoint * v.cos());
+                }
+                OperationType::PowF32(b, exp) => {
+                    let vb = b.0.as_ref().borrow().cache.clone().unwrap();
+                    let ve = exp.0.as_ref().borrow().cache.clone().unwrap();
+                    Self::accumulate(
+                        b,
+                        adjoint.clone() * ve.clone() * vb.powf(&(ve.clone() - T::one())),
+                    );
+                    Self::accumulate(exp, adjoint * vb.powf(&ve) * vb.ln());
+                }
+                OperationType::Apply(inputs, _, df) => {
+                    if let Some(df) = df {
+                        let vals: Vec<T> = inputs.iter().map(Self::value).collect();
+                        let partials = df(&vals);
+                        for (input, partial) in inputs.iter().zip(partials) {
+                            Self::accumulate(input, adjoint.clone() * partial);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(grads)
     }
 
+    pub fn set<I: Into<T>>(&self, new_val: I) {
+        let mut node = self.0.as_ref().borrow_mut();
+        if let OperationType::Input(ref _s) = node.op_type {
+            node.cache.replace(new_val.into());
+            node.version += 1;
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::Graph;
+    use std::rc::Rc;
 
 
     /// Round to decimal digits
@@ -139,7 +364,7 @@ mod test {
 
     #[test]
     fn test() {
-        let x1 = Graph::create_input("x1");
+        let x1: Graph<f32> = Graph::create_input("x1");
         let x2 = Graph::create_input("x2");
         let x3 = Graph::create_input("x3");
         let x4 = Graph::create_input("x4");
@@ -162,16 +387,99 @@ mod test {
         x3.set(3f32);
         x4.set(3f32);
 
-        let mut result = graph.compute();
+        let mut result = graph.compute().unwrap();
         result = round(result, 5);
         assert_eq!(result, -0.32727);
 
         x1.set(2f32);
         x2.set(3f32);
         x3.set(4f32);
-        result = graph.compute();
+        result = graph.compute().unwrap();
         result = round(result, 5);
         assert_eq!(result, -0.56656);
     }
 
+    #[test]
+    fn test_backward() {
+        let x1: Graph<f32> = Graph::create_input("x1");
+        let x2 = Graph::create_input("x2");
+        let x3 = Graph::create_input("x3");
+        let x4 = Graph::create_input("x4");
+
+        let graph = Graph::add(
+            x1.clone(),
+            Graph::mul(
+                x2.clone(),
+                Graph::sin(
+                    Graph::add(
+                        x2.clone(),
+                        Graph::pow_f32(x3.clone(), x4.clone())
+                    )
+                )
+            )
+        );
+
+        x1.set(1f32);
+        x2.set(2f32);
+        x3.set(3f32);
+        x4.set(3f32);
+
+        let grads = graph.backward().unwrap();
+
+        // f = x1 + x2 * sin(x2 + x3^x4)
+        // let s = x2 + x3^x4 = 2 + 27 = 29
+        // df/dx1 = 1
+        // df/dx2 = sin(s) + x2 * cos(s)
+        // df/dx3 = x2 * cos(s) * x4 * x3^(x4 - 1)
+        // df/dx4 = x2 * cos(s) * x3^x4 * ln(x3)
+        let s = 29f32;
+        assert_eq!(round(grads["x1"], 5), 1.0);
+        assert_eq!(round(grads["x2"], 5), round(s.sin() + 2f32 * s.cos(), 5));
+        assert_eq!(
+            round(grads["x3"], 5),
+            round(2f32 * s.cos() * 3f32 * 3f32.powf(2f32), 5)
+        );
+        assert_eq!(
+            round(grads["x4"], 5),
+            round(2f32 * s.cos() * 3f32.powf(3f32) * 3f32.ln(), 5)
+        );
+    }
+
+    #[test]
+    fn test_incremental_recompute() {
+        let x1: Graph<f32> = Graph::create_input("x1");
+        let x2 = Graph::create_input("x2");
+
+        let graph = Graph::mul(x1.clone(), x2.clone());
+
+        x1.set(2f32);
+        x2.set(3f32);
+        assert_eq!(graph.compute().unwrap(), 6f32);
+
+        // Changing a single input and recomputing reflects only that change.
+        x1.set(5f32);
+        assert_eq!(graph.compute().unwrap(), 15f32);
+
+        // Recomputing without any `set` in between reuses the cache.
+        assert_eq!(graph.compute().unwrap(), 15f32);
+    }
+
+    #[test]
+    fn test_apply() {
+        let x: Graph<f32> = Graph::create_input("x");
+
+        // A custom `exp` node: exp'(x) == exp(x).
+        let exp = Graph::apply_with_grad(
+            vec![x.clone()],
+            Rc::new(|v: &[f32]| v[0].exp()),
+            Rc::new(|v: &[f32]| vec![v[0].exp()]),
+        );
+
+        x.set(1f32);
+        assert_eq!(round(exp.compute().unwrap(), 5), round(1f32.exp(), 5));
+
+        let grads = exp.backward().unwrap();
+        assert_eq!(round(grads["x"], 5), round(1f32.exp(), 5));
+    }
+
 }
@@ -0,0 +1,275 @@
+//! Thread-safe flavour of [`Graph`](crate::Graph).
+//!
+//! The single-threaded graph is backed by `Rc<RefCell<_>>` and therefore
+//! neither `Send` nor `Sync`, which rules out evaluating independent subgraphs
+//! on several threads. This module mirrors the public surface
+//! (`create_input`/`add`/`mul`/`sin`/`pow_f32`/`compute`/`set`) on top of
+//! `Arc<RwLock<_>>`, and additionally offers [`Graph::compute_parallel`], which
+//! evaluates nodes at the same depth concurrently via scoped threads.
+
+use std::{
+    collections::HashMap,
+    ops::{Add, Mul, Sub},
+    sync::{Arc, RwLock},
+};
+
+use crate::Transcendental;
+
+pub struct Graph<T>(Arc<RwLock<Node<T>>>);
+
+impl<T> Clone for Graph<T> {
+    fn clone(&self) -> Self {
+        Graph(self.0.clone())
+    }
+}
+
+enum OperationType<T> {
+    Input(String),
+    Add(Graph<T>, Graph<T>),
+    Mul(Graph<T>, Graph<T>),
+    Sin(Graph<T>),
+    PowF32(Graph<T>, Graph<T>),
+}
+
+pub struct Node<T> {
+    /// Operation performed by the node
+    op_type: OperationType<T>,
+    /// Nodes which must be recalculated when the value in current node is changed
+    dependent_nodes: Vec<Graph<T>>,
+    /// Cached value
+    cache: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new(op_type: OperationType<T>, cache: Option<T>) -> Node<T> {
+        Node {
+            op_type,
+            dependent_nodes: vec![],
+            cache,
+        }
+    }
+
+    fn wrap(self) -> Graph<T> {
+        Graph(Arc::new(RwLock::new(self)))
+    }
+}
+
+impl<T> Graph<T>
+where
+    T: Transcendental + Clone + Add<Output = T> + Mul<Output = T> + Sub<Output = T> + Send + Sync,
+{
+    pub fn create_input<I: Into<String>>(val: I) -> Graph<T> {
+        Node::new(OperationType::Input(val.into()), None).wrap()
+    }
+
+    pub fn add(op1: Graph<T>, op2: Graph<T>) -> Graph<T> {
+        let node = Node::new(OperationType::Add(op1.clone(), op2.clone()), None).wrap();
+        Self::add_dependent_node(&node, op1);
+        Self::add_dependent_node(&node, op2);
+        node
+    }
+
+    pub fn mul(op1: Graph<T>, op2: Graph<T>) -> Graph<T> {
+        let node = Node::new(OperationType::Mul(op1.clone(), op2.clone()), None).wrap();
+        Self::add_dependent_node(&node, op1);
+        Self::add_dependent_node(&node, op2);
+        node
+    }
+
+    pub fn pow_f32(b: Graph<T>, exp: Graph<T>) -> Graph<T> {
+        let node = Node::new(OperationType::PowF32(b.clone(), exp.clone()), None).wrap();
+        Self::add_dependent_node(&node, b);
+        Self::add_dependent_node(&node, exp);
+        node
+    }
+
+    pub fn sin(op: Graph<T>) -> Graph<T> {
+        let node = Node::new(OperationType::Sin(op.clone()), None).wrap();
+        Self::add_dependent_node(&node, op);
+        node
+    }
+
+    fn traverse(node: &Graph<T>) -> T {
+        {
+            let n = node.0.read().unwrap();
+            if let Some(cache) = &n.cache {
+                return cache.clone();
+            }
+        }
+
+        let res = match &node.0.read().unwrap().op_type {
+            OperationType::Input(ref _s) => node.0.read().unwrap().cache.clone().unwrap(),
+            OperationType::Add(op1, op2) => Self::traverse(op1) + Self::traverse(op2),
+            OperationType::Mul(op1, op2) => Self::traverse(op1) * Self::traverse(op2),
+            OperationType::Sin(op) => Self::traverse(op).sin(),
+            OperationType::PowF32(b, exp) => Self::traverse(b).powf(&Self::traverse(exp)),
+        };
+        node.0.write().unwrap().cache.replace(res.clone());
+        res
+    }
+
+    pub fn compute(&self) -> T {
+        Self::traverse(self)
+    }
+
+    /// Collect the nodes feeding `node` in topological order (every operand
+    /// appears before the node that consumes it). Shared `Arc` nodes are
+    /// visited once, so the DAG is flattened without duplicates.
+    fn topo_order(
+        node: &Graph<T>,
+        visited: &mut HashMap<*const RwLock<Node<T>>, usize>,
+        order: &mut Vec<Graph<T>>,
+    ) {
+        let ptr = Arc::as_ptr(&node.0);
+        if visited.contains_key(&ptr) {
+            return;
+        }
+        match &node.0.read().unwrap().op_type {
+            OperationType::Input(ref _s) => {}
+            OperationType::Add(op1, op2)
+            | OperationType::Mul(op1, op2)
+            | OperationType::PowF32(op1, op2) => {
+                Self::topo_order(op1, visited, order);
+                Self::topo_order(op2, visited, order);
+            }
+            OperationType::Sin(op) => {
+                Self::topo_order(op, visited, order);
+            }
+        }
+        visited.insert(ptr, order.len());
+        order.push(node.clone());
+    }
+
+    /// Read the (already cached) value of a node.
+    fn value(node: &Graph<T>) -> T {
+        node.0.read().unwrap().cache.clone().unwrap()
+    }
+
+    /// Evaluate a single node assuming every operand is already cached, and
+    /// store the result in its own cache. Safe to call concurrently on distinct
+    /// nodes because it only write-locks the node itself.
+    fn eval_cached(node: &Graph<T>) {
+        let res = {
+            let guard = node.0.read().unwrap();
+            match &guard.op_type {
+                OperationType::Input(ref _s) => return,
+                OperationType::Add(op1, op2) => Self::value(op1) + Self::value(op2),
+                OperationType::Mul(op1, op2) => Self::value(op1) * Self::value(op2),
+                OperationType::Sin(op) => Self::value(op).sin(),
+                OperationType::PowF32(b, exp) => Self::value(b).powf(&Self::value(exp)),
+            }
+        };
+        node.0.write().unwrap().cache.replace(res);
+    }
+
+    /// Depth of a node: `0` for inputs, otherwise one more than the deepest
+    /// operand. Computed from an already-built topological `order`.
+    fn depths(
+        order: &[Graph<T>],
+        index: &HashMap<*const RwLock<Node<T>>, usize>,
+    ) -> Vec<usize> {
+        let mut depth = vec![0usize; order.len()];
+        for (i, node) in order.iter().enumerate() {
+            let child_depth = |op: &Graph<T>| depth[index[&Arc::as_ptr(&op.0)]];
+            depth[i] = match &node.0.read().unwrap().op_type {
+                OperationType::Input(ref _s) => 0,
+                OperationType::Add(op1, op2)
+                | OperationType::Mul(op1, op2)
+                | OperationType::PowF32(op1, op2) => 1 + child_depth(op1).max(child_depth(op2)),
+                OperationType::Sin(op) => 1 + child_depth(op),
+            };
+        }
+        depth
+    }
+
+    /// Evaluate the graph, computing all nodes at the same depth concurrently.
+    ///
+    /// Builds a topological order once, buckets nodes by depth, then fills the
+    /// caches level by level. Within a level the nodes are mutually independent,
+    /// so they are evaluated on scoped threads — a meaningful speed-up for wide
+    /// graphs of expensive operations.
+    pub fn compute_parallel(&self) -> T {
+        let mut index = HashMap::new();
+        let mut order = vec![];
+        Self::topo_order(self, &mut index, &mut order);
+
+        let depth = Self::depths(&order, &index);
+        let max_depth = depth.iter().copied().max().unwrap_or(0);
+
+        let mut levels: Vec<Vec<Graph<T>>> = vec![vec![]; max_depth + 1];
+        for (node, &d) in order.iter().zip(depth.iter()) {
+            levels[d].push(node.clone());
+        }
+
+        for level in &levels {
+            std::thread::scope(|s| {
+                for node in level {
+                    s.spawn(move || Self::eval_cached(node));
+                }
+            });
+        }
+
+        Self::value(self)
+    }
+
+    fn clear_cash(node: &Graph<T>) {
+        let mut node = node.0.write().unwrap();
+        let _ = node.cache.take();
+        let deps = node.dependent_nodes.clone();
+        drop(node);
+        for dep in &deps {
+            Self::clear_cash(dep);
+        }
+    }
+
+    pub fn set<I: Into<T>>(&self, new_val: I) {
+        let is_input = matches!(self.0.read().unwrap().op_type, OperationType::Input(_));
+        if is_input {
+            Self::clear_cash(self);
+            self.0.write().unwrap().cache.replace(new_val.into());
+        }
+    }
+
+    fn add_dependent_node(&self, op: Graph<T>) {
+        op.0.write().unwrap().dependent_nodes.push(self.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sync::Graph;
+
+    /// Round to decimal digits
+    fn round(x: f32, precision: u32) -> f32 {
+        let m = 10i32.pow(precision) as f32;
+        (x * m).round() / m
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let x1: Graph<f32> = Graph::create_input("x1");
+        let x2 = Graph::create_input("x2");
+        let x3 = Graph::create_input("x3");
+        let x4 = Graph::create_input("x4");
+
+        let graph = Graph::add(
+            x1.clone(),
+            Graph::mul(
+                x2.clone(),
+                Graph::sin(Graph::add(x2.clone(), Graph::pow_f32(x3.clone(), x4.clone()))),
+            ),
+        );
+
+        x1.set(1f32);
+        x2.set(2f32);
+        x3.set(3f32);
+        x4.set(3f32);
+
+        assert_eq!(round(graph.compute_parallel(), 5), -0.32727);
+
+        x1.set(2f32);
+        x2.set(3f32);
+        x3.set(4f32);
+        assert_eq!(round(graph.compute_parallel(), 5), -0.56656);
+    }
+}